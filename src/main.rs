@@ -6,7 +6,7 @@ use handlers::register;
 
 pub mod recipe_model {
 
-    use juniper::GraphQLObject;
+    use juniper::{GraphQLInputObject, GraphQLObject};
     use serde::{Deserialize, Serialize};
 
     #[derive(Serialize, Deserialize, GraphQLObject)]
@@ -32,6 +32,51 @@ pub mod recipe_model {
         tags: Vec<String>,
         media: Vec<MediaRef>,
     }
+
+    #[derive(Serialize, Deserialize, GraphQLInputObject)]
+    #[graphql(description = "An ingredient used in a recipe")]
+    pub struct IngredientInput {
+        name: String,
+        qty: String,
+    }
+
+    #[derive(Serialize, Deserialize, GraphQLInputObject)]
+    #[graphql(description = "A reference to some Media in the recipe")]
+    pub struct MediaRefInput {
+        anchor: String,
+        url: String,
+    }
+
+    #[derive(Serialize, Deserialize, GraphQLInputObject)]
+    #[graphql(description = "The fields of a recipe to create or replace")]
+    pub struct RecipeInput {
+        title: String,
+        ingredients: Vec<IngredientInput>,
+        instructions: Vec<String>,
+        tags: Vec<String>,
+        media: Vec<MediaRefInput>,
+    }
+
+    // A file submitted per the GraphQL multipart request spec. The scalar value
+    // itself is just the `map` key the multipart handler used to stash the
+    // uploaded bytes on `Context`; resolvers look the bytes up from there.
+    #[derive(Clone, Debug)]
+    pub struct Upload(pub String);
+
+    #[juniper::graphql_scalar(description = "A file uploaded via the GraphQL multipart request spec")]
+    impl<S: juniper::ScalarValue> GraphQLScalar for Upload {
+        fn resolve(&self) -> juniper::Value {
+            juniper::Value::scalar(self.0.clone())
+        }
+
+        fn from_input_value(value: &juniper::InputValue) -> Option<Upload> {
+            value.as_string_value().map(|key| Upload(key.to_owned()))
+        }
+
+        fn from_str(value: juniper::ScalarToken) -> juniper::ParseScalarResult<S> {
+            <String as juniper::ParseScalarValue<S>>::from_str(value)
+        }
+    }
 }
 mod error {
     use std::fmt;
@@ -119,21 +164,261 @@ mod error {
             write!(f, "{}", self.message())
         }
     }
+
+    // Lets handlers propagate an `AppError` with `?` and have actix turn it
+    // into an HTTP response, used by the multipart upload endpoint.
+    impl actix_web::ResponseError for AppError {
+        fn error_response(&self) -> actix_web::HttpResponse {
+            use actix_web::http::StatusCode;
+
+            let status = match self.error_type {
+                AppErrorType::NotFoundError => StatusCode::NOT_FOUND,
+                AppErrorType::InvalidField => StatusCode::BAD_REQUEST,
+                AppErrorType::IOError => StatusCode::BAD_REQUEST,
+                AppErrorType::DbError => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+
+            actix_web::HttpResponse::build(status)
+                .json(serde_json::json!({ "error": self.message() }))
+        }
+    }
+}
+
+mod query_limits {
+    use std::collections::{HashMap, HashSet};
+
+    use juniper::{
+        ast::{Definition, Fragment, Selection},
+        parser::{parse_document_source, Spanning},
+        DefaultScalarValue, InputValue, ScalarValue,
+    };
+
+    use crate::error::{AppError, AppErrorType};
+
+    // Guardrails enforced against every incoming query before it ever reaches
+    // Mongo, read from `MAX_QUERY_DEPTH` / `MAX_QUERY_COMPLEXITY` in `main`.
+    #[derive(Clone, Copy)]
+    pub struct QueryLimits {
+        pub max_depth: usize,
+        pub max_complexity: usize,
+    }
+
+    const DEFAULT_MAX_DEPTH: usize = 10;
+    const DEFAULT_MAX_COMPLEXITY: usize = 1000;
+
+    impl QueryLimits {
+        pub fn from_env() -> Self {
+            let max_depth = std::env::var("MAX_QUERY_DEPTH")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_MAX_DEPTH);
+            let max_complexity = std::env::var("MAX_QUERY_COMPLEXITY")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_MAX_COMPLEXITY);
+
+            QueryLimits {
+                max_depth,
+                max_complexity,
+            }
+        }
+
+        // Parses `query` and walks every operation's selection set, rejecting it
+        // if the nesting depth or summed field cost exceeds the configured limit.
+        // `variables` is the operation's `variables` map, used to resolve
+        // `first`/`limit` arguments that were passed by reference (`$name`)
+        // rather than as inline literals.
+        pub fn enforce(
+            &self,
+            query: &str,
+            variables: Option<&InputValue<DefaultScalarValue>>,
+        ) -> Result<(), AppError> {
+            let document = parse_document_source::<DefaultScalarValue>(query, &Default::default())
+                .map_err(|error| AppError {
+                    message: Some(format!("Could not parse query: {}", error.item)),
+                    cause: None,
+                    error_type: AppErrorType::InvalidField,
+                })?;
+
+            let fragments: HashMap<&str, &Fragment<DefaultScalarValue>> = document
+                .iter()
+                .filter_map(|definition| match definition {
+                    Definition::Fragment(Spanning { item, .. }) => {
+                        Some((item.name.item.as_str(), item))
+                    }
+                    Definition::Operation(_) => None,
+                })
+                .collect();
+
+            for definition in &document {
+                let selection_set = match definition {
+                    Definition::Operation(Spanning { item, .. }) => &item.selection_set,
+                    Definition::Fragment(_) => continue,
+                };
+
+                let (depth, complexity) =
+                    measure(selection_set, 1, &fragments, variables, &mut HashSet::new())?;
+
+                if depth > self.max_depth {
+                    return Err(AppError {
+                        message: Some(format!(
+                            "Query depth {} exceeds the limit of {}",
+                            depth, self.max_depth
+                        )),
+                        cause: None,
+                        error_type: AppErrorType::InvalidField,
+                    });
+                }
+
+                if complexity > self.max_complexity {
+                    return Err(AppError {
+                        message: Some(format!(
+                            "Query complexity {} exceeds the limit of {}",
+                            complexity, self.max_complexity
+                        )),
+                        cause: None,
+                        error_type: AppErrorType::InvalidField,
+                    });
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    // Returns the deepest nesting level and the summed per-field cost below
+    // `selection_set`. List fields are weighted by their `first`/`limit` argument.
+    // Fragment spreads and inline fragments are resolved and measured at the
+    // depth of the spread itself, so hiding expensive selections behind a
+    // fragment doesn't let them dodge the limits. `visiting` tracks the names of
+    // fragments currently being expanded on this path so a cycle of fragments
+    // spreading each other is rejected instead of recursing forever.
+    fn measure<S: ScalarValue>(
+        selection_set: &[Selection<S>],
+        depth: usize,
+        fragments: &HashMap<&str, &Fragment<S>>,
+        variables: Option<&InputValue<S>>,
+        visiting: &mut HashSet<String>,
+    ) -> Result<(usize, usize), AppError> {
+        let mut max_depth = depth;
+        let mut complexity = 0;
+
+        for selection in selection_set {
+            let field = match selection {
+                Selection::Field(Spanning { item, .. }) => item,
+                Selection::FragmentSpread(Spanning { item, .. }) => {
+                    let name = item.name.item.as_str();
+                    if !visiting.insert(name.to_owned()) {
+                        return Err(AppError {
+                            message: Some(format!(
+                                "Fragment \"{}\" is part of a fragment spread cycle",
+                                name
+                            )),
+                            cause: None,
+                            error_type: AppErrorType::InvalidField,
+                        });
+                    }
+
+                    if let Some(fragment) = fragments.get(name) {
+                        let (nested_depth, nested_complexity) = measure(
+                            &fragment.selection_set,
+                            depth,
+                            fragments,
+                            variables,
+                            visiting,
+                        )?;
+                        max_depth = max_depth.max(nested_depth);
+                        complexity += nested_complexity;
+                    }
+
+                    visiting.remove(name);
+                    continue;
+                }
+                Selection::InlineFragment(Spanning { item, .. }) => {
+                    let (nested_depth, nested_complexity) =
+                        measure(&item.selection_set, depth, fragments, variables, visiting)?;
+                    max_depth = max_depth.max(nested_depth);
+                    complexity += nested_complexity;
+                    continue;
+                }
+            };
+
+            let weight = field
+                .arguments
+                .as_ref()
+                .and_then(|args| {
+                    args.item
+                        .items
+                        .iter()
+                        .find(|(name, _)| name.item == "first" || name.item == "limit")
+                })
+                .and_then(|(_, value)| resolve_int_argument(&value.item, variables))
+                .map(|n| n.max(1) as usize)
+                .unwrap_or(1);
+            complexity += weight;
+
+            if let Some(nested) = &field.selection_set {
+                let (nested_depth, nested_complexity) =
+                    measure(nested, depth + 1, fragments, variables, visiting)?;
+                max_depth = max_depth.max(nested_depth);
+                complexity += nested_complexity;
+            }
+        }
+
+        Ok((max_depth, complexity))
+    }
+
+    // Resolves an argument value to an `i32`, following a `$variable` reference
+    // through the operation's `variables` map if the argument wasn't passed as
+    // an inline literal.
+    fn resolve_int_argument<S: ScalarValue>(
+        value: &InputValue<S>,
+        variables: Option<&InputValue<S>>,
+    ) -> Option<i32> {
+        match value.as_variable_name() {
+            Some(name) => variables?
+                .as_object_value()?
+                .get(name)?
+                .as_scalar_value::<i32>()
+                .copied(),
+            None => value.as_scalar_value::<i32>().copied(),
+        }
+    }
 }
 
 mod recipe_schema {
-    use futures::stream::StreamExt;
-    use juniper::graphql_object;
+    use std::pin::Pin;
+
+    use futures::stream::{Stream, StreamExt};
+    use juniper::{graphql_object, graphql_subscription, GraphQLObject};
     use mongodb::{
-        bson::{self, doc},
+        bson::{self, doc, oid::ObjectId},
+        options::{
+            ChangeStreamOptions, FindOneAndUpdateOptions, FindOptions, FullDocumentType,
+            ReturnDocument,
+        },
         Collection,
     };
 
-    use crate::{error::AppError, error::AppErrorType, recipe_model::Recipe};
+    use std::collections::HashMap;
+
+    use crate::{
+        error::AppError,
+        error::AppErrorType,
+        recipe_model::{Recipe, RecipeInput, Upload},
+    };
     use log::info;
 
+    // Bytes received for one part of a multipart GraphQL request, keyed by the
+    // `map` key the client used to reference it from `operations`.
+    pub struct UploadedFile {
+        pub filename: String,
+        pub data: Vec<u8>,
+    }
+
     pub struct Context {
         pub collection: Collection,
+        pub uploads: HashMap<String, UploadedFile>,
     }
 
     // To make our context usable by Juniper, we have to implement a marker trait.
@@ -187,57 +472,587 @@ mod recipe_schema {
 
         // Can add additional fields to a field query by adding the values as Option
         // async fn recipe(context: &Context, title: String, ingredients: Option<Vec<String>>) -> Result<Recipe, AppError> {
+
+        // Bounded, forward-paginating alternative to `recipes`.
+        async fn recipes_connection(
+            context: &Context,
+            first: Option<i32>,
+            after: Option<String>,
+        ) -> Result<RecipeConnection, AppError> {
+            let first = first.unwrap_or(10).max(1) as i64;
+
+            let mut filter = doc! {};
+            if let Some(after) = after {
+                let id = decode_cursor(&after)?;
+                filter.insert("_id", doc! { "$gt": id });
+            }
+
+            let options = FindOptions::builder()
+                .sort(doc! { "_id": 1 })
+                .limit(first + 1)
+                .build();
+
+            let mut cursor = context.collection.find(filter, options).await?;
+            let mut docs = Vec::new();
+            while let Some(doc) = cursor.next().await {
+                docs.push(doc?);
+            }
+
+            let has_next_page = docs.len() as i64 > first;
+            docs.truncate(first as usize);
+
+            let total_count = context.collection.count_documents(doc! {}, None).await? as i32;
+
+            let end_cursor = docs.last().map(|doc| encode_cursor(doc));
+            let edges = docs
+                .into_iter()
+                .map(|doc| {
+                    let cursor = encode_cursor(&doc);
+                    let node: Recipe = bson::from_document(doc)?;
+                    Ok(RecipeEdge { cursor, node })
+                })
+                .collect::<Result<Vec<_>, AppError>>()?;
+
+            Ok(RecipeConnection {
+                edges,
+                page_info: PageInfo {
+                    has_next_page,
+                    end_cursor,
+                },
+                total_count,
+            })
+        }
+
+        // Discovery API combining free-text search with tag and ingredient filters.
+        async fn search(
+            context: &Context,
+            query: Option<String>,
+            tags: Option<Vec<String>>,
+            ingredients: Option<Vec<String>>,
+        ) -> Result<Vec<Recipe>, AppError> {
+            let mut clauses = Vec::new();
+
+            if let Some(query) = query {
+                clauses.push(doc! { "$text": { "$search": query } });
+            }
+
+            if let Some(tags) = tags {
+                clauses.push(doc! { "tags": { "$all": tags } });
+            }
+
+            if let Some(ingredients) = ingredients {
+                for ingredient in ingredients {
+                    clauses.push(doc! {
+                        "ingredients": {
+                            "$elemMatch": {
+                                "name": { "$regex": escape_regex(&ingredient), "$options": "i" },
+                            }
+                        }
+                    });
+                }
+            }
+
+            let filter = if clauses.is_empty() {
+                doc! {}
+            } else {
+                doc! { "$and": clauses }
+            };
+
+            let mut recipes = Vec::<Recipe>::new();
+            let mut cursor = context.collection.find(filter, None).await?;
+            while let Some(doc) = cursor.next().await {
+                recipes.push(bson::from_document(doc?)?);
+            }
+            Ok(recipes)
+        }
     }
 
-    // A root schema consists of a query and a mutation.
+    // Escapes PCRE metacharacters so a user-supplied ingredient name is matched
+    // literally rather than interpreted as a MongoDB `$regex` pattern.
+    fn escape_regex(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if "\\^$.|?*+()[]{}".contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    // Encodes a document's `_id` as an opaque, base64 pagination cursor.
+    fn encode_cursor(doc: &bson::Document) -> String {
+        let id = doc.get_object_id("_id").map(ObjectId::to_hex).unwrap_or_default();
+        base64::encode(id)
+    }
+
+    // Decodes a pagination cursor back into the `ObjectId` it was built from.
+    fn decode_cursor(cursor: &str) -> Result<ObjectId, AppError> {
+        let decoded = base64::decode(cursor).map_err(|error| AppError {
+            message: Some("Invalid pagination cursor".into()),
+            cause: Some(error.to_string()),
+            error_type: AppErrorType::InvalidField,
+        })?;
+        let hex = String::from_utf8(decoded).map_err(|error| AppError {
+            message: Some("Invalid pagination cursor".into()),
+            cause: Some(error.to_string()),
+            error_type: AppErrorType::InvalidField,
+        })?;
+        ObjectId::parse_str(hex).map_err(|error| AppError {
+            message: Some("Invalid pagination cursor".into()),
+            cause: Some(error.to_string()),
+            error_type: AppErrorType::InvalidField,
+        })
+    }
+
+    #[derive(GraphQLObject)]
+    #[graphql(description = "A single page of recipes and pagination metadata")]
+    pub struct RecipeConnection {
+        edges: Vec<RecipeEdge>,
+        page_info: PageInfo,
+        total_count: i32,
+    }
+
+    #[derive(GraphQLObject)]
+    #[graphql(description = "A recipe together with its opaque pagination cursor")]
+    pub struct RecipeEdge {
+        cursor: String,
+        node: Recipe,
+    }
+
+    #[derive(GraphQLObject)]
+    #[graphql(description = "Pagination metadata for a connection")]
+    pub struct PageInfo {
+        has_next_page: bool,
+        end_cursor: Option<String>,
+    }
+
+    pub struct Mutation;
+
+    #[graphql_object(context = Context)]
+    impl Mutation {
+        async fn create_recipe(context: &Context, input: RecipeInput) -> Result<Recipe, AppError> {
+            let doc = bson::to_document(&input)?;
+            context.collection.insert_one(doc.clone(), None).await?;
+            Ok(bson::from_document(doc)?)
+        }
+
+        async fn update_recipe(
+            context: &Context,
+            title: String,
+            input: RecipeInput,
+        ) -> Result<Recipe, AppError> {
+            let replacement = bson::to_document(&input)?;
+            let res = context
+                .collection
+                .find_one_and_replace(doc! { "title": title }, replacement.clone(), None)
+                .await?;
+
+            match res {
+                Some(_) => Ok(bson::from_document(replacement)?),
+                None => Err(AppError {
+                    message: Some("Recipe not found".into()),
+                    cause: None,
+                    error_type: AppErrorType::NotFoundError,
+                }),
+            }
+        }
+
+        async fn delete_recipe(context: &Context, title: String) -> Result<Recipe, AppError> {
+            let res = context
+                .collection
+                .find_one_and_delete(doc! { "title": title }, None)
+                .await?;
+
+            match res {
+                Some(doc) => Ok(bson::from_document(doc)?),
+                None => Err(AppError {
+                    message: Some("Recipe not found".into()),
+                    cause: None,
+                    error_type: AppErrorType::NotFoundError,
+                }),
+            }
+        }
+
+        async fn upload_recipe_media(
+            context: &Context,
+            title: String,
+            file: Upload,
+        ) -> Result<Recipe, AppError> {
+            let uploaded = context.uploads.get(&file.0).ok_or_else(|| AppError {
+                message: Some("No uploaded file matched this variable".into()),
+                cause: None,
+                error_type: AppErrorType::IOError,
+            })?;
+
+            let media = store_media(&title, uploaded).await?;
+
+            let res = context
+                .collection
+                .find_one_and_update(
+                    doc! { "title": &title },
+                    doc! { "$push": { "media": media } },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                )
+                .await?;
+
+            match res {
+                Some(doc) => Ok(bson::from_document(doc)?),
+                None => Err(AppError {
+                    message: Some("Recipe not found".into()),
+                    cause: None,
+                    error_type: AppErrorType::NotFoundError,
+                }),
+            }
+        }
+    }
+
+    // Writes an uploaded file to the local media directory and returns a
+    // `MediaRef` document pointing at it. Swap this for a GridFS bucket write
+    // if the media directory outgrows local disk.
+    async fn store_media(title: &str, uploaded: &UploadedFile) -> Result<bson::Document, AppError> {
+        tokio::fs::create_dir_all("./media").await?;
+
+        let safe_name = uploaded.filename.replace(
+            |c: char| !(c.is_alphanumeric() || c == '.' || c == '-' || c == '_'),
+            "_",
+        );
+        let stored_name = format!("{}-{}", uuid::Uuid::new_v4(), safe_name);
+        let path = std::path::Path::new("./media").join(&stored_name);
+        tokio::fs::write(&path, &uploaded.data).await?;
+
+        Ok(doc! {
+            "anchor": title,
+            "url": format!("/media/{}", stored_name),
+        })
+    }
+
+    pub struct Subscription;
+
+    type RecipeStream = Pin<Box<dyn Stream<Item = Result<Recipe, AppError>> + Send>>;
+
+    #[graphql_subscription(context = Context)]
+    impl Subscription {
+        // Streams every recipe inserted into the collection after subscribing.
+        async fn recipe_added(context: &Context) -> RecipeStream {
+            watch_recipes(context, doc! { "operationType": "insert" }).await
+        }
+
+        // Streams every recipe inserted or modified after subscribing.
+        async fn recipe_changed(context: &Context) -> RecipeStream {
+            watch_recipes(
+                context,
+                doc! { "operationType": { "$in": ["insert", "update", "replace"] } },
+            )
+            .await
+        }
+    }
+
+    // Opens a change stream on the recipes collection filtered by `match_stage`
+    // and decodes each event's full document into a `Recipe`.
+    async fn watch_recipes(context: &Context, match_stage: bson::Document) -> RecipeStream {
+        let collection = context.collection.clone();
+        let pipeline = vec![doc! { "$match": match_stage }];
+
+        let stream = async_stream::stream! {
+            let options = ChangeStreamOptions::builder()
+                .full_document(Some(FullDocumentType::UpdateLookup))
+                .build();
+
+            let mut change_stream = match collection.watch(pipeline, options).await {
+                Ok(change_stream) => change_stream,
+                Err(error) => {
+                    yield Err(AppError::from(error));
+                    return;
+                }
+            };
+
+            while let Some(event) = change_stream.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(error) => {
+                        yield Err(AppError::from(error));
+                        continue;
+                    }
+                };
+
+                if let Some(full_document) = event.full_document {
+                    yield bson::from_document(full_document).map_err(AppError::from);
+                }
+            }
+        };
+
+        Box::pin(stream)
+    }
+
+    // A root schema consists of a query, a mutation and a subscription.
     // Request queries can be executed against a RootNode.
-    pub type Schema = juniper::RootNode<
-        'static,
-        Query,
-        juniper::EmptyMutation<Context>,
-        juniper::EmptySubscription<Context>,
-    >;
+    pub type Schema = juniper::RootNode<'static, Query, Mutation, Subscription>;
 
     pub fn create_schema() -> Schema {
-        Schema::new(
-            Query,
-            juniper::EmptyMutation::new(),
-            juniper::EmptySubscription::new(),
-        )
+        Schema::new(Query, Mutation, Subscription)
     }
 }
 
 mod handlers {
-    use actix_web::{web, HttpResponse};
+    use std::collections::HashMap;
+
+    use actix_multipart::Multipart;
+    use actix_web::{web, Error, HttpRequest, HttpResponse};
+    use futures::TryStreamExt;
     use juniper::http::{graphiql::graphiql_source, GraphQLRequest};
+    use juniper_subscriptions::Coordinator;
     use mongodb::Collection;
 
-    use crate::recipe_schema::{create_schema, Context, Schema};
+    use crate::{
+        error::{AppError, AppErrorType},
+        query_limits::QueryLimits,
+        recipe_schema::{create_schema, Context, Schema, UploadedFile},
+    };
+
+    // Limits applied to the GraphQL multipart upload endpoint.
+    const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+    const MAX_UPLOAD_FILES: usize = 4;
+    // The `operations`/`map` parts are just JSON, not file payloads, so they get
+    // a much tighter cap than `MAX_UPLOAD_BYTES` while still being bounded.
+    const MAX_METADATA_BYTES: usize = 64 * 1024;
+
+    // Mirrors the standard GraphQL-over-HTTP request body. We deserialize into
+    // this (rather than straight into `juniper::http::GraphQLRequest`) so the raw
+    // query text is available to `QueryLimits::enforce` before execution.
+    #[derive(serde::Deserialize)]
+    struct GraphQLBody {
+        query: String,
+        #[serde(rename = "operationName")]
+        operation_name: Option<String>,
+        variables: Option<juniper::InputValue>,
+    }
 
     async fn graphql_playground() -> HttpResponse {
         HttpResponse::Ok()
             .content_type("text/html; charset=utf-8")
-            .body(graphiql_source("/graphql", None))
+            .body(graphiql_source("/graphql", Some("/subscriptions")))
     }
 
     async fn graphql(
         schema: web::Data<Schema>,
-        data: web::Json<GraphQLRequest>,
+        body: web::Json<GraphQLBody>,
+        collection: web::Data<Collection>,
+        limits: web::Data<QueryLimits>,
+    ) -> Result<HttpResponse, AppError> {
+        limits.enforce(&body.query, body.variables.as_ref())?;
+
+        let body = body.into_inner();
+        let request = GraphQLRequest::new(body.query, body.operation_name, body.variables);
+
+        let ctx = Context {
+            collection: collection.get_ref().to_owned(),
+            uploads: HashMap::new(),
+        };
+        let res = request.execute(&schema, &ctx).await;
+
+        Ok(HttpResponse::Ok().json(res))
+    }
+
+    // Upgrades to a websocket speaking the `graphql-ws` subprotocol and serves
+    // `Subscription` fields off the change streams opened in `recipe_schema`.
+    async fn subscriptions(
+        req: HttpRequest,
+        stream: web::Payload,
+        coordinator: web::Data<Coordinator<'static, Schema>>,
         collection: web::Data<Collection>,
-    ) -> HttpResponse {
+    ) -> Result<HttpResponse, Error> {
+        let ctx = Context {
+            collection: collection.get_ref().to_owned(),
+            uploads: HashMap::new(),
+        };
+        juniper_actix::subscriptions::subscriptions_handler(
+            req,
+            stream,
+            coordinator.into_inner(),
+            ctx,
+        )
+        .await
+    }
+
+    // Implements the GraphQL multipart request spec: an `operations` part holding
+    // the usual `{query, variables}` body, a `map` part linking file parts to
+    // variable paths, and the file parts themselves. Each mapped variable is
+    // rewritten to the multipart key, which `Upload::from_input_value` carries
+    // into the resolver that looks the bytes up in `Context::uploads`.
+    async fn graphql_upload(
+        schema: web::Data<Schema>,
+        collection: web::Data<Collection>,
+        limits: web::Data<QueryLimits>,
+        mut payload: Multipart,
+    ) -> Result<HttpResponse, AppError> {
+        let mut operations: Option<serde_json::Value> = None;
+        let mut map: HashMap<String, Vec<String>> = HashMap::new();
+        let mut uploads: HashMap<String, UploadedFile> = HashMap::new();
+
+        while let Some(mut field) = payload.try_next().await.map_err(io_error)? {
+            let disposition = field.content_disposition().cloned();
+            let name = disposition
+                .as_ref()
+                .and_then(|cd| cd.get_name().map(str::to_owned))
+                .unwrap_or_default();
+            let filename = disposition.and_then(|cd| cd.get_filename().map(str::to_owned));
+
+            // `operations`/`map` are small JSON blobs bounded by MAX_METADATA_BYTES;
+            // everything else is a file part bounded by MAX_UPLOAD_BYTES/FILES.
+            let is_file_part = !matches!(name.as_str(), "operations" | "map");
+            if is_file_part && uploads.len() >= MAX_UPLOAD_FILES {
+                return Err(too_many_files());
+            }
+
+            let part_limit = if is_file_part {
+                MAX_UPLOAD_BYTES
+            } else {
+                MAX_METADATA_BYTES
+            };
+
+            let mut bytes = Vec::new();
+            while let Some(chunk) = field.try_next().await.map_err(io_error)? {
+                if bytes.len() + chunk.len() > part_limit {
+                    return Err(if is_file_part {
+                        file_too_large()
+                    } else {
+                        metadata_too_large()
+                    });
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+
+            match name.as_str() {
+                "operations" => operations = Some(serde_json::from_slice(&bytes).map_err(io_error)?),
+                "map" => map = serde_json::from_slice(&bytes).map_err(io_error)?,
+                key => {
+                    uploads.insert(
+                        key.to_owned(),
+                        UploadedFile {
+                            filename: filename.unwrap_or_else(|| key.to_owned()),
+                            data: bytes,
+                        },
+                    );
+                }
+            }
+        }
+
+        let mut operations = operations.ok_or_else(missing_operations)?;
+
+        let query = operations
+            .get("query")
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(missing_operations)?;
+        let variables: Option<juniper::InputValue> = operations
+            .get("variables")
+            .cloned()
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(io_error)?;
+        limits.enforce(query, variables.as_ref())?;
+
+        for (file_key, paths) in &map {
+            for path in paths {
+                set_json_path(&mut operations, path, serde_json::Value::String(file_key.clone()));
+            }
+        }
+        let request: GraphQLRequest = serde_json::from_value(operations).map_err(io_error)?;
+
         let ctx = Context {
             collection: collection.get_ref().to_owned(),
+            uploads,
         };
-        let res = data.execute(&schema, &ctx).await;
+        let res = request.execute(&schema, &ctx).await;
+        Ok(HttpResponse::Ok().json(res))
+    }
 
-        HttpResponse::Ok().json(res)
+    // Sets the value at a dotted path (e.g. `variables.file`) inside a JSON
+    // document, per the multipart spec's `map` convention.
+    fn set_json_path(target: &mut serde_json::Value, path: &str, value: serde_json::Value) {
+        let mut cursor = target;
+        for segment in path.split('.') {
+            cursor = match segment.parse::<usize>() {
+                Ok(index) if cursor.is_array() => &mut cursor[index],
+                _ => &mut cursor[segment],
+            };
+        }
+        *cursor = value;
+    }
+
+    fn io_error(error: impl std::fmt::Display) -> AppError {
+        AppError {
+            message: Some("Malformed multipart upload request".into()),
+            cause: Some(error.to_string()),
+            error_type: AppErrorType::IOError,
+        }
+    }
+
+    fn missing_operations() -> AppError {
+        AppError {
+            message: Some("Missing `operations` part".into()),
+            cause: None,
+            error_type: AppErrorType::IOError,
+        }
+    }
+
+    fn too_many_files() -> AppError {
+        AppError {
+            message: Some(format!("Uploads are limited to {} files", MAX_UPLOAD_FILES)),
+            cause: None,
+            error_type: AppErrorType::IOError,
+        }
+    }
+
+    fn file_too_large() -> AppError {
+        AppError {
+            message: Some(format!(
+                "Uploaded file exceeds the {} byte limit",
+                MAX_UPLOAD_BYTES
+            )),
+            cause: None,
+            error_type: AppErrorType::IOError,
+        }
+    }
+
+    fn metadata_too_large() -> AppError {
+        AppError {
+            message: Some(format!(
+                "The `operations`/`map` part exceeds the {} byte limit",
+                MAX_METADATA_BYTES
+            )),
+            cause: None,
+            error_type: AppErrorType::IOError,
+        }
+    }
+
+    // Routes multipart GraphQL requests to `graphql_upload` and everything
+    // else to the plain JSON `graphql` handler.
+    fn is_multipart(req: &actix_web::dev::RequestHead) -> bool {
+        req.headers()
+            .get(actix_web::http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.starts_with("multipart/form-data"))
+            .unwrap_or(false)
     }
 
     pub fn register(config: &mut web::ServiceConfig) {
         config
             .data(create_schema())
-            .route("/graphql", web::post().to(graphql))
-            .route("/graphiql", web::get().to(graphql_playground));
+            .data(Coordinator::new(create_schema()))
+            .service(
+                web::resource("/graphql")
+                    .route(
+                        web::post()
+                            .guard(actix_web::guard::fn_guard(is_multipart))
+                            .to(graphql_upload),
+                    )
+                    .route(web::post().to(graphql)),
+            )
+            .route("/graphiql", web::get().to(graphql_playground))
+            .route("/subscriptions", web::get().to(subscriptions));
     }
 }
 
@@ -257,9 +1072,14 @@ async fn main() -> Result<(), AppError> {
     let database = client.database("recipedb");
     let collection = database.collection("recipes");
 
+    // MAX_QUERY_DEPTH / MAX_QUERY_COMPLEXITY bound how deep/expensive a query
+    // can be before handlers::graphql(_upload) reject it outright.
+    let query_limits = query_limits::QueryLimits::from_env();
+
     actix_web::HttpServer::new(move || {
         actix_web::App::new()
             .data(collection.clone())
+            .data(query_limits)
             .wrap(middleware::Logger::default())
             .configure(register)
             .default_service(web::to(|| async { "404" }))